@@ -4,6 +4,8 @@ use crate::Workspace;
 use failure::{bail, Error, ResultExt};
 use log::info;
 use std::borrow::Cow;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
 
 pub(crate) const MAIN_TOOLCHAIN_NAME: &str = "stable";
 
@@ -13,7 +15,13 @@ pub(crate) const MAIN_TOOLCHAIN_NAME: &str = "stable";
 /// [rust-lang/rust][rustc] repo's CI artifacts storage. and it provides the tool to install and use it.
 ///
 /// [rustc]: https://github.com/rust-lang/rust
-#[derive(serde::Serialize, serde::Deserialize, PartialEq, Eq, Hash, Debug, Clone)]
+///
+/// The `CI` variant's queued components/targets use a `Mutex` rather than a `RefCell` for
+/// interior mutability, so that `Toolchain` stays `Sync` as well as `Send` and can be shared
+/// (e.g. behind an `Arc<Toolchain>`) across the worker threads a crater-style harness runs
+/// builds on. Because `Mutex` doesn't implement `PartialEq`/`Eq`/`Hash`/`Clone`, `Toolchain`
+/// implements all four by hand below instead of deriving them.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
 #[serde(rename_all = "kebab-case", tag = "type")]
 pub enum Toolchain {
     /// Toolchain available through rustup and distributed from
@@ -22,6 +30,10 @@ pub enum Toolchain {
         /// The name of the toolchain, which is the same you'd use with `rustup toolchain install
         /// <name>`.
         name: Cow<'static, str>,
+        /// The installation profile, controlling how much of the toolchain rustup downloads.
+        /// Defaults to [`Profile::Default`](Profile::Default) for backward compatibility.
+        #[serde(default)]
+        profile: Profile,
     },
     /// CI artifact from the [rust-lang/rust] repo. Each merged PR has its own full build
     /// available for a while after it's been merged, identified by the merge commit sha. **There
@@ -35,31 +47,243 @@ pub enum Toolchain {
         /// Whether you want to download a standard or "alt" build. "alt" builds have extra
         /// compiler assertions enabled.
         alt: bool,
+        /// Components to install alongside the toolchain. Unlike the `Dist` variant, these
+        /// can't be added to an already-installed CI toolchain, so they're collected here and
+        /// applied the next time [`install`](Toolchain::install) is called. Wrapped in a
+        /// `Mutex` so [`add_component`](Toolchain::add_component) can queue a component without
+        /// requiring a `&mut Toolchain`, while keeping `Toolchain` `Sync`.
+        #[serde(default)]
+        components: Mutex<Vec<Cow<'static, str>>>,
+        /// Targets to install alongside the toolchain. Unlike the `Dist` variant, these can't
+        /// be added to an already-installed CI toolchain, so they're collected here and applied
+        /// the next time [`install`](Toolchain::install) is called. Wrapped in a `Mutex` so
+        /// [`add_target`](Toolchain::add_target) can queue a target without requiring a
+        /// `&mut Toolchain`, while keeping `Toolchain` `Sync`.
+        #[serde(default)]
+        targets: Mutex<Vec<Cow<'static, str>>>,
     },
 }
 
+impl Clone for Toolchain {
+    fn clone(&self) -> Self {
+        match self {
+            Self::Dist { name, profile } => Self::Dist {
+                name: name.clone(),
+                profile: *profile,
+            },
+            Self::CI {
+                sha,
+                alt,
+                components,
+                targets,
+            } => Self::CI {
+                sha: sha.clone(),
+                alt: *alt,
+                components: Mutex::new(components.lock().unwrap().clone()),
+                targets: Mutex::new(targets.lock().unwrap().clone()),
+            },
+        }
+    }
+}
+
+impl PartialEq for Toolchain {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (
+                Self::Dist { name, profile },
+                Self::Dist {
+                    name: other_name,
+                    profile: other_profile,
+                },
+            ) => name == other_name && profile == other_profile,
+            (
+                Self::CI {
+                    sha,
+                    alt,
+                    components,
+                    targets,
+                },
+                Self::CI {
+                    sha: other_sha,
+                    alt: other_alt,
+                    components: other_components,
+                    targets: other_targets,
+                },
+            ) => {
+                sha == other_sha
+                    && alt == other_alt
+                    && *components.lock().unwrap() == *other_components.lock().unwrap()
+                    && *targets.lock().unwrap() == *other_targets.lock().unwrap()
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Toolchain {}
+
+impl Hash for Toolchain {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Self::Dist { name, profile } => {
+                0u8.hash(state);
+                name.hash(state);
+                profile.hash(state);
+            }
+            Self::CI {
+                sha,
+                alt,
+                components,
+                targets,
+            } => {
+                1u8.hash(state);
+                sha.hash(state);
+                alt.hash(state);
+                components.lock().unwrap().hash(state);
+                targets.lock().unwrap().hash(state);
+            }
+        }
+    }
+}
+
+/// The `rustup` installation profile, controlling how much of a [`Toolchain::Dist`] toolchain is
+/// downloaded. See the [rustup documentation][rustup-profiles] for more details.
+///
+/// [rustup-profiles]: https://rust-lang.github.io/rustup/concepts/profiles.html
+#[derive(serde::Serialize, serde::Deserialize, PartialEq, Eq, Hash, Debug, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum Profile {
+    /// Only the components needed to build and test: `rustc`, `rust-std` and `cargo`.
+    Minimal,
+    /// `minimal` plus `rust-docs`, `rustfmt` and `clippy`. This is rustup's own default.
+    Default,
+    /// Every component rustup knows about, including `rust-src` and `rust-docs-json`.
+    Complete,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile::Default
+    }
+}
+
+impl Profile {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Profile::Minimal => "minimal",
+            Profile::Default => "default",
+            Profile::Complete => "complete",
+        }
+    }
+}
+
+/// The concrete rustc version backing a [`Toolchain`], as reported by
+/// `rustc --version --verbose`. See [`Toolchain::rustc_version`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RustcVersion {
+    /// The compiler's semver release, e.g. `1.49.0` or `1.50.0-nightly`.
+    pub semver: String,
+    /// The commit hash the compiler was built from. `None` for releases built without VCS
+    /// information available.
+    pub commit_hash: Option<String>,
+    /// The date of the commit the compiler was built from. `None` for releases built without
+    /// VCS information available.
+    pub commit_date: Option<String>,
+    /// The host triple the compiler runs on.
+    pub host: String,
+}
+
+fn parse_rustc_version(stdout: &[String]) -> Result<RustcVersion, Error> {
+    let mut lines = stdout.iter();
+    let first = lines
+        .next()
+        .ok_or_else(|| failure::err_msg("`rustc --version --verbose` produced no output"))?;
+    if !first.starts_with("rustc ") {
+        bail!(
+            "unexpected output from `rustc --version --verbose`: {}",
+            first
+        );
+    }
+
+    let mut semver = None;
+    let mut commit_hash = None;
+    let mut commit_date = None;
+    let mut host = None;
+    for line in lines {
+        let mut parts = line.splitn(2, ':');
+        let (key, value) = match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) => (key.trim(), value.trim()),
+            _ => continue,
+        };
+        match key {
+            "release" => semver = Some(value.to_string()),
+            "commit-hash" if value != "unknown" => commit_hash = Some(value.to_string()),
+            "commit-date" if value != "unknown" => commit_date = Some(value.to_string()),
+            "host" => host = Some(value.to_string()),
+            _ => {}
+        }
+    }
+
+    Ok(RustcVersion {
+        semver: semver
+            .ok_or_else(|| failure::err_msg("missing `release` field in rustc version output"))?,
+        commit_hash,
+        commit_date,
+        host: host
+            .ok_or_else(|| failure::err_msg("missing `host` field in rustc version output"))?,
+    })
+}
+
 impl Toolchain {
     pub(crate) const MAIN: Toolchain = Toolchain::Dist {
         name: Cow::Borrowed(MAIN_TOOLCHAIN_NAME),
+        profile: Profile::Default,
     };
 
     /// Download and install the toolchain.
     pub fn install(&self, workspace: &Workspace) -> Result<(), Error> {
         match self {
-            Self::Dist { name } => init_toolchain_from_dist(workspace, name)?,
-            Self::CI { sha, alt } => init_toolchain_from_ci(workspace, *alt, sha)?,
+            Self::Dist { name, profile } => init_toolchain_from_dist(workspace, name, *profile)?,
+            Self::CI {
+                sha,
+                alt,
+                components,
+                targets,
+            } => {
+                let components = components.lock().unwrap();
+                let targets = targets.lock().unwrap();
+                init_toolchain_from_ci(workspace, *alt, sha, &components, &targets)?
+            }
         }
 
         Ok(())
     }
 
     /// Download and install a component for the toolchain.
+    ///
+    /// On CI toolchains the component is only queued up: `rustup-toolchain-install-master`
+    /// can't add components to an already-installed toolchain, so it's passed `-c <name>` the
+    /// next time [`install`](Toolchain::install) is called. Declare all the CI components you
+    /// need before calling `install`.
     pub fn add_component(&self, workspace: &Workspace, name: &str) -> Result<(), Error> {
+        if let Self::CI { components, .. } = self {
+            components.lock().unwrap().push(name.to_string().into());
+            return Ok(());
+        }
         self.add_rustup_thing(workspace, "component", name)
     }
 
     /// Download and install a target for the toolchain.
+    ///
+    /// On CI toolchains the target is only queued up: `rustup-toolchain-install-master` can't
+    /// add targets to an already-installed toolchain, so it's passed `-t <name>` the next time
+    /// [`install`](Toolchain::install) is called. Declare all the CI targets you need before
+    /// calling `install`.
     pub fn add_target(&self, workspace: &Workspace, name: &str) -> Result<(), Error> {
+        if let Self::CI { targets, .. } = self {
+            targets.lock().unwrap().push(name.to_string().into());
+            return Ok(());
+        }
         self.add_rustup_thing(workspace, "target", name)
     }
 
@@ -69,9 +293,6 @@ impl Toolchain {
         thing: &str,
         name: &str,
     ) -> Result<(), Error> {
-        if let Self::CI { .. } = self {
-            bail!("installing {} on CI toolchains is not supported yet", thing);
-        }
         let toolchain_name = self.rustup_name();
         info!(
             "installing {} {} for toolchain {}",
@@ -100,6 +321,58 @@ impl Toolchain {
         Ok(())
     }
 
+    /// Query the concrete rustc version backing this toolchain, including the commit hash and
+    /// date it was built from. This is especially useful for the [`CI`](Toolchain::CI) variant,
+    /// where the rustup name is just an opaque commit sha.
+    pub fn rustc_version(&self, workspace: &Workspace) -> Result<RustcVersion, Error> {
+        let rustc = self.rustc();
+        let result = Command::new(workspace, &rustc)
+            .args(&["--version", "--verbose"])
+            .log_output(false)
+            .run_capture()
+            .with_context(|_| format!("failed to get the rustc version of toolchain {}", self))?;
+
+        parse_rustc_version(&result.stdout)
+    }
+
+    /// Check whether this toolchain is currently installed in the rustwide workspace, without
+    /// attempting to install it. Useful to skip a redundant [`install`](Toolchain::install)
+    /// call across process restarts.
+    pub fn is_installed(&self, workspace: &Workspace) -> Result<bool, Error> {
+        let name = self.rustup_name();
+        let result = Command::new(workspace, &RUSTUP)
+            .args(&["toolchain", "list"])
+            .log_output(false)
+            .run_capture()
+            .with_context(|_| "unable to list installed toolchains via rustup".to_string())?;
+        let host = host_triple(workspace)?;
+
+        Ok(toolchain_is_listed(&result.stdout, &name, &host))
+    }
+
+    /// Verify that this toolchain is installed and its binaries actually resolve, failing fast
+    /// with an actionable error instead of letting a missing or corrupt toolchain surface as an
+    /// opaque failure mid-build.
+    pub fn verify(&self, workspace: &Workspace) -> Result<(), Error> {
+        if !self.is_installed(workspace)? {
+            bail!("toolchain {} is not installed; call install() first", self);
+        }
+
+        let rustc = self.rustc();
+        Command::new(workspace, &rustc)
+            .args(&["--version"])
+            .log_output(false)
+            .run()
+            .with_context(|_| {
+                format!(
+                    "toolchain {} is installed but its binaries failed to run",
+                    self
+                )
+            })?;
+
+        Ok(())
+    }
+
     /// Return a runnable object configured to run `cargo` with this toolchain. This method is
     /// intended to be used with [`rustwide::cmd::Command`](cmd/struct.Command.html).
     ///
@@ -110,7 +383,7 @@ impl Toolchain {
     /// # use std::error::Error;
     /// # fn main() -> Result<(), Box<dyn Error>> {
     /// # let workspace = WorkspaceBuilder::new("".as_ref(), "").init()?;
-    /// let toolchain = Toolchain::Dist { name: "beta".into() };
+    /// let toolchain = Toolchain::Dist { name: "beta".into(), profile: Default::default() };
     /// Command::new(&workspace, toolchain.cargo())
     ///     .args(&["check"])
     ///     .run()?;
@@ -118,26 +391,53 @@ impl Toolchain {
     /// # }
     /// ```
     pub fn cargo<'a>(&'a self) -> impl Runnable + 'a {
-        struct CargoBin<'a>(&'a Toolchain);
+        self.tool("cargo")
+    }
+
+    /// Return a runnable object configured to run `rustc` with this toolchain. This method is
+    /// intended to be used with [`rustwide::cmd::Command`](cmd/struct.Command.html).
+    pub fn rustc<'a>(&'a self) -> impl Runnable + 'a {
+        self.tool("rustc")
+    }
 
-        impl Runnable for CargoBin<'_> {
+    /// Return a runnable object configured to run `rustdoc` with this toolchain. This method is
+    /// intended to be used with [`rustwide::cmd::Command`](cmd/struct.Command.html).
+    pub fn rustdoc<'a>(&'a self) -> impl Runnable + 'a {
+        self.tool("rustdoc")
+    }
+
+    /// Return a runnable object configured to run an arbitrary binary shipped by this toolchain
+    /// (for example `clippy-driver`, `rustfmt` or `miri`) with this toolchain. This method is
+    /// intended to be used with [`rustwide::cmd::Command`](cmd/struct.Command.html).
+    pub fn tool<'a>(&'a self, name: &'a str) -> impl Runnable + 'a {
+        struct ToolchainBin<'a> {
+            toolchain: &'a Toolchain,
+            name: &'a str,
+        }
+
+        impl Runnable for ToolchainBin<'_> {
             fn name(&self) -> Binary {
-                Binary::ManagedByRustwide("cargo".into())
+                Binary::ManagedByRustwide(self.name.into())
             }
 
             fn prepare_command<'w, 'pl>(&self, cmd: Command<'w, 'pl>) -> Command<'w, 'pl> {
-                cmd.args(&[format!("+{}", self.0.rustup_name())])
+                cmd.args(&[format!("+{}", self.toolchain.rustup_name())])
             }
         }
 
-        CargoBin(self)
+        ToolchainBin {
+            toolchain: self,
+            name,
+        }
     }
 
     fn rustup_name(&self) -> String {
         match self {
-            Self::Dist { name } => name.to_string(),
-            Self::CI { sha, alt: false } => sha.to_string(),
-            Self::CI { sha, alt: true } => format!("{}-alt", sha),
+            Self::Dist { name, .. } => name.to_string(),
+            Self::CI {
+                sha, alt: false, ..
+            } => sha.to_string(),
+            Self::CI { sha, alt: true, .. } => format!("{}-alt", sha),
         }
     }
 }
@@ -148,17 +448,64 @@ impl std::fmt::Display for Toolchain {
     }
 }
 
-fn init_toolchain_from_dist(workspace: &Workspace, toolchain: &str) -> Result<(), Error> {
+/// The default host triple rustup installs toolchains for on this machine, as reported by the
+/// `Default host: <triple>` line of `rustup show`.
+fn host_triple(workspace: &Workspace) -> Result<String, Error> {
+    let result = Command::new(workspace, &RUSTUP)
+        .args(&["show"])
+        .log_output(false)
+        .run_capture()
+        .with_context(|_| "unable to query the default host triple via rustup".to_string())?;
+
+    result
+        .stdout
+        .iter()
+        .find_map(|line| line.strip_prefix("Default host: "))
+        .map(|triple| triple.trim().to_string())
+        .ok_or_else(|| {
+            failure::err_msg("unable to find the default host triple in `rustup show` output")
+        })
+}
+
+/// Whether `name` is listed as installed in the output of `rustup toolchain list`. Channel
+/// toolchains (e.g. `stable`) are listed suffixed with the resolved `host_triple` (e.g.
+/// `stable-x86_64-unknown-linux-gnu`), while CI/sha toolchains are listed verbatim, so both
+/// forms — but only an exact match of either, never a prefix — count as installed.
+fn toolchain_is_listed(lines: &[String], name: &str, host_triple: &str) -> bool {
+    let triple_name = format!("{}-{}", name, host_triple);
+    lines
+        .iter()
+        .filter_map(|line| line.split_whitespace().next())
+        .any(|token| token == name || token == triple_name)
+}
+
+fn init_toolchain_from_dist(
+    workspace: &Workspace,
+    toolchain: &str,
+    profile: Profile,
+) -> Result<(), Error> {
     info!("installing toolchain {}", toolchain);
     Command::new(workspace, &RUSTUP)
-        .args(&["toolchain", "install", toolchain])
+        .args(&[
+            "toolchain",
+            "install",
+            toolchain,
+            "--profile",
+            profile.as_str(),
+        ])
         .run()
         .with_context(|_| format!("unable to install toolchain {} via rustup", toolchain))?;
 
     Ok(())
 }
 
-fn init_toolchain_from_ci(workspace: &Workspace, alt: bool, sha: &str) -> Result<(), Error> {
+fn init_toolchain_from_ci(
+    workspace: &Workspace,
+    alt: bool,
+    sha: &str,
+    components: &[Cow<str>],
+    targets: &[Cow<str>],
+) -> Result<(), Error> {
     if alt {
         info!("installing toolchain {}-alt", sha);
     } else {
@@ -166,6 +513,14 @@ fn init_toolchain_from_ci(workspace: &Workspace, alt: bool, sha: &str) -> Result
     }
 
     let mut args = vec![sha, "-c", "cargo"];
+    for component in components {
+        args.push("-c");
+        args.push(component.as_ref());
+    }
+    for target in targets {
+        args.push("-t");
+        args.push(target.as_ref());
+    }
     if alt {
         args.push("--alt");
     }
@@ -182,3 +537,96 @@ fn init_toolchain_from_ci(workspace: &Workspace, alt: bool, sha: &str) -> Result
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(text: &str) -> Vec<String> {
+        text.lines().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn parse_rustc_version_with_vcs_info() {
+        let version = parse_rustc_version(&lines(
+            "rustc 1.49.0 (e1884a8e3 2020-12-29)\n\
+             binary: rustc\n\
+             commit-hash: e1884a8e3c3e813aa64c3c8c82fd53f381e32c85\n\
+             commit-date: 2020-12-29\n\
+             host: x86_64-unknown-linux-gnu\n\
+             release: 1.49.0\n\
+             LLVM version: 11.0",
+        ))
+        .unwrap();
+
+        assert_eq!(version.semver, "1.49.0");
+        assert_eq!(
+            version.commit_hash.as_deref(),
+            Some("e1884a8e3c3e813aa64c3c8c82fd53f381e32c85")
+        );
+        assert_eq!(version.commit_date.as_deref(), Some("2020-12-29"));
+        assert_eq!(version.host, "x86_64-unknown-linux-gnu");
+    }
+
+    #[test]
+    fn parse_rustc_version_without_vcs_info() {
+        let version = parse_rustc_version(&lines(
+            "rustc 1.49.0 (unknown)\n\
+             binary: rustc\n\
+             commit-hash: unknown\n\
+             commit-date: unknown\n\
+             host: x86_64-unknown-linux-gnu\n\
+             release: 1.49.0\n\
+             LLVM version: 11.0",
+        ))
+        .unwrap();
+
+        assert_eq!(version.semver, "1.49.0");
+        assert_eq!(version.commit_hash, None);
+        assert_eq!(version.commit_date, None);
+        assert_eq!(version.host, "x86_64-unknown-linux-gnu");
+    }
+
+    #[test]
+    fn parse_rustc_version_rejects_malformed_first_line() {
+        let err = parse_rustc_version(&lines("not rustc output")).unwrap_err();
+        assert!(err.to_string().contains("unexpected output"));
+    }
+
+    #[test]
+    fn toolchain_is_listed_matches_channel_toolchain_by_host_triple() {
+        let installed = lines(
+            "stable-x86_64-unknown-linux-gnu (default)\n\
+             nightly-2021-01-01-x86_64-unknown-linux-gnu",
+        );
+
+        assert!(toolchain_is_listed(
+            &installed,
+            "stable",
+            "x86_64-unknown-linux-gnu"
+        ));
+    }
+
+    #[test]
+    fn toolchain_is_listed_matches_ci_sha_verbatim() {
+        let installed = lines("4e1c5f0c8a990e3f2e1a1c1f1234abcd5678ef90");
+
+        assert!(toolchain_is_listed(
+            &installed,
+            "4e1c5f0c8a990e3f2e1a1c1f1234abcd5678ef90",
+            "x86_64-unknown-linux-gnu"
+        ));
+    }
+
+    #[test]
+    fn toolchain_is_listed_does_not_match_on_dated_prefix_collision() {
+        // A dated nightly build must not make a plain, unpinned "nightly" look installed.
+        let installed = lines("nightly-2021-01-01-x86_64-unknown-linux-gnu");
+
+        assert!(!toolchain_is_listed(
+            &installed,
+            "nightly",
+            "x86_64-unknown-linux-gnu"
+        ));
+    }
+}